@@ -12,9 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{cmp, thread};
+use std::thread;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::time::Duration;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicBool, Ordering};
 use time;
 
@@ -26,10 +29,208 @@ use p2p::{self, Peer, Peers};
 use types::Error;
 use util::LOGGER;
 
+/// How many blocks we're willing to have in flight to a single peer at once.
+/// Keeps one slow or greedy peer from soaking up the whole request budget.
+const MAX_BLOCKS_IN_TRANSIT_PER_PEER: usize = 16;
+
+/// How long we'll wait for a block we've requested before giving up on it
+/// and letting some other peer have a go.
+const BLOCK_DOWNLOAD_TIMEOUT: i64 = 30;
+
+/// Peers that rack up this many timed out block requests are considered
+/// unreliable and are skipped in favor of healthier peers.
+const MAX_BLOCK_DOWNLOAD_TIMEOUTS: u32 = 3;
+
+/// How long a peer's timeout strikes linger before they're forgiven. A peer
+/// that stumbled early (e.g. while it was still warming up) and has been
+/// timeout-free since gets a clean slate instead of being blacklisted for
+/// the rest of the session.
+const PEER_TIMEOUT_DECAY: i64 = 10 * 60;
+
+/// Tracks blocks we've already requested so body_sync can spread requests
+/// across the available peers instead of hammering whichever one
+/// `more_work_peer()` happens to return, and so it never asks twice for a
+/// block that's already on its way. Also keeps a running count of how many
+/// requests each peer has timed out on, so we can stop favoring peers that
+/// never answer, alongside the time of its most recent timeout so that
+/// count can decay away.
+struct BlockDownloads {
+	in_transit: Mutex<HashMap<Hash, (SocketAddr, time::Tm)>>,
+	timeouts: Mutex<HashMap<SocketAddr, (u32, time::Tm)>>,
+}
+
+impl BlockDownloads {
+	fn new() -> BlockDownloads {
+		BlockDownloads {
+			in_transit: Mutex::new(HashMap::new()),
+			timeouts: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Drop any in-transit entries for blocks we've since received or
+	/// orphaned, freeing up their peer's slot for new requests. Returns how
+	/// many of the dropped entries were blocks we actually received, so
+	/// callers can track progress.
+	fn prune_received(&self, chain: &chain::Chain) -> u64 {
+		let mut in_transit = self.in_transit.lock().unwrap();
+		let mut received = 0;
+		in_transit.retain(|hash, _| {
+			if chain.get_block(hash).is_ok() {
+				received += 1;
+				false
+			} else {
+				!chain.is_orphan(hash)
+			}
+		});
+		received
+	}
+
+	/// Requests that have been outstanding for longer than
+	/// `BLOCK_DOWNLOAD_TIMEOUT` are treated as failed: the hash is freed up
+	/// for another peer to try, and a strike is recorded against whichever
+	/// peer failed to deliver. Strikes older than `PEER_TIMEOUT_DECAY` are
+	/// forgiven so a peer that's been well-behaved for a while isn't stuck
+	/// unreliable forever.
+	fn reap_timeouts(&self) {
+		let now = time::now_utc();
+		let mut in_transit = self.in_transit.lock().unwrap();
+		let timed_out = in_transit
+			.iter()
+			.filter(|&(_, &(_, ref sent))| now - *sent > time::Duration::seconds(BLOCK_DOWNLOAD_TIMEOUT))
+			.map(|(hash, &(addr, _))| (*hash, addr))
+			.collect::<Vec<_>>();
+
+		let mut timeouts = self.timeouts.lock().unwrap();
+		for (hash, addr) in timed_out {
+			in_transit.remove(&hash);
+			let entry = timeouts.entry(addr).or_insert((0, now));
+			entry.0 += 1;
+			entry.1 = now;
+			debug!(
+				LOGGER,
+				"sync: block {} timed out waiting on {} ({} timeouts)", hash, addr, entry.0,
+			);
+		}
+
+		timeouts.retain(|_, &mut (_, last)| now - last < time::Duration::seconds(PEER_TIMEOUT_DECAY));
+	}
+
+	fn is_in_transit(&self, hash: &Hash) -> bool {
+		self.in_transit.lock().unwrap().contains_key(hash)
+	}
+
+	fn in_transit_count(&self, addr: &SocketAddr) -> usize {
+		self.in_transit
+			.lock()
+			.unwrap()
+			.values()
+			.filter(|&&(ref a, _)| a == addr)
+			.count()
+	}
+
+	/// Whether this peer has timed out often enough that we'd rather not
+	/// hand it more work right now.
+	// TODO - once p2p exposes a way to ban/score a peer directly, report
+	// these timeouts there instead of just steering body_sync away locally.
+	fn is_reliable(&self, addr: &SocketAddr) -> bool {
+		let count = self.timeouts
+			.lock()
+			.unwrap()
+			.get(addr)
+			.map(|&(count, _)| count)
+			.unwrap_or(0);
+		count < MAX_BLOCK_DOWNLOAD_TIMEOUTS
+	}
+
+	fn insert(&self, hash: Hash, addr: SocketAddr) {
+		self.in_transit
+			.lock()
+			.unwrap()
+			.insert(hash, (addr, time::now_utc()));
+	}
+}
+
+/// The phase of syncing we're currently in. Lets callers outside the sync
+/// thread (the REST API, the TUI) render something more useful than a bare
+/// "syncing: true".
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyncState {
+	/// Not syncing, just accepting gossiped blocks and transactions
+	NoSync,
+	/// Waiting for enough peers to decide whether syncing is even needed
+	AwaitingPeers,
+	/// Downloading headers from a peer with more work than us
+	HeaderSync { current_height: u64, highest_height: u64 },
+	/// Downloading the txhashset snapshot for fast sync
+	TxHashsetDownload,
+	/// Downloading full blocks to bring the body chain up to the headers
+	BodySync { current_height: u64, highest_height: u64 },
+	/// Fully caught up, just receiving blocks and transactions through gossip
+	Synced,
+}
+
+impl SyncState {
+	/// Anything other than `NoSync`/`Synced` means we're actively working
+	/// through one of the sync phases.
+	pub fn is_syncing(&self) -> bool {
+		match *self {
+			SyncState::NoSync | SyncState::Synced => false,
+			_ => true,
+		}
+	}
+}
+
+/// Shared, queryable sync progress, owned by the sync thread and read by
+/// whatever wants to report on it (REST API, TUI).
+pub struct SyncStatus {
+	state: SyncState,
+	highest_height: u64,
+	peers_with_more_work: usize,
+	blocks_received: u64,
+}
+
+impl SyncStatus {
+	pub fn new() -> SyncStatus {
+		SyncStatus {
+			state: SyncState::NoSync,
+			highest_height: 0,
+			peers_with_more_work: 0,
+			blocks_received: 0,
+		}
+	}
+
+	/// The current sync phase.
+	pub fn state(&self) -> SyncState {
+		self.state.clone()
+	}
+
+	/// Height advertised by our most-work peer, as last seen. Left unchanged
+	/// on ticks where no such height could be read.
+	pub fn highest_height(&self) -> u64 {
+		self.highest_height
+	}
+
+	/// How many peers are currently advertising more work than we have.
+	pub fn peers_with_more_work(&self) -> usize {
+		self.peers_with_more_work
+	}
+
+	/// How many blocks we've received so far this sync session.
+	pub fn blocks_received(&self) -> u64 {
+		self.blocks_received
+	}
+
+	fn update(&mut self, state: SyncState) {
+		if self.state != state {
+			debug!(LOGGER, "sync_state: {:?} -> {:?}", self.state, state);
+			self.state = state;
+		}
+	}
+}
+
 /// Starts the syncing loop, just spawns two threads that loop forever
 pub fn run_sync(
-	currently_syncing: Arc<AtomicBool>,
-	awaiting_peers: Arc<AtomicBool>,
+	sync_state: Arc<RwLock<SyncStatus>>,
 	peers: Arc<p2p::Peers>,
 	chain: Arc<chain::Chain>,
 	skip_sync_wait: bool,
@@ -44,12 +245,18 @@ pub fn run_sync(
 			let mut prev_header_sync = prev_body_sync.clone();
 			let mut prev_fast_sync = prev_body_sync.clone() - time::Duration::seconds(5 * 60);
 			let mut highest_height = 0;
+			let block_downloads = Arc::new(BlockDownloads::new());
+			let header_rate = Arc::new(HeaderSyncTracker::new());
+			let checkpoint_tracker = Arc::new(ForkCheckpointTracker::new());
 
 			// initial sleep to give us time to peer with some nodes
 			if !skip_sync_wait {
-				awaiting_peers.store(true, Ordering::Relaxed);
+				sync_state.write().unwrap().update(SyncState::AwaitingPeers);
 				thread::sleep(Duration::from_secs(30));
-				awaiting_peers.store(false, Ordering::Relaxed);
+				// back to no decision made yet, so the first `needs_syncing`
+				// call takes the threshold branch instead of mistaking
+				// "we were waiting for peers" for "we're already syncing"
+				sync_state.write().unwrap().update(SyncState::NoSync);
 			}
 
 			// fast sync has 3 states:
@@ -67,13 +274,19 @@ pub fn run_sync(
 
 				// is syncing generally needed when we compare our state with others
 				let (syncing, most_work_height) =
-					needs_syncing(currently_syncing.as_ref(), peers.clone(), chain.clone());
+					needs_syncing(&sync_state, peers.clone(), chain.clone());
 
 				if most_work_height > 0 {
 					// we can occasionally get a most work height of 0 if read locks fail
 					highest_height = most_work_height;
 				}
 
+				{
+					let mut status = sync_state.write().unwrap();
+					status.highest_height = highest_height;
+					status.peers_with_more_work = peers.more_work_peers().len();
+				}
+
 				// in archival nodes (no fast sync) we just consider we have the whole
 				// state already, then fast sync triggers if other peers are much
 				// further ahead
@@ -90,7 +303,13 @@ pub fn run_sync(
 				if syncing {
 					// run the header sync every 10s
 					if current_time - prev_header_sync > time::Duration::seconds(10) {
-						header_sync(peers.clone(), chain.clone());
+						header_sync(
+							&sync_state,
+							&header_rate,
+							&checkpoint_tracker,
+							peers.clone(),
+							chain.clone(),
+						);
 						prev_header_sync = current_time;
 					}
 
@@ -98,19 +317,22 @@ pub fn run_sync(
 					if !fast_sync_enabled
 						&& current_time - prev_body_sync > time::Duration::seconds(5)
 					{
-						body_sync(peers.clone(), chain.clone());
+						body_sync(&sync_state, peers.clone(), chain.clone(), &block_downloads);
 						prev_body_sync = current_time;
 					}
 
 					// run fast sync if applicable, every 5 min
 					if fast_sync_enabled && header_head.height == highest_height {
 						if current_time - prev_fast_sync > time::Duration::seconds(5 * 60) {
+							sync_state
+								.write()
+								.unwrap()
+								.update(SyncState::TxHashsetDownload);
 							fast_sync(peers.clone(), chain.clone(), &header_head);
 							prev_fast_sync = current_time;
 						}
 					}
 				}
-				currently_syncing.store(syncing, Ordering::Relaxed);
 
 				thread::sleep(Duration::from_secs(1));
 
@@ -121,11 +343,25 @@ pub fn run_sync(
 		});
 }
 
-fn body_sync(peers: Arc<Peers>, chain: Arc<chain::Chain>) {
+fn body_sync(
+	sync_state: &Arc<RwLock<SyncStatus>>,
+	peers: Arc<Peers>,
+	chain: Arc<chain::Chain>,
+	downloads: &Arc<BlockDownloads>,
+) {
 	let body_head: chain::Tip = chain.head().unwrap();
 	let header_head: chain::Tip = chain.get_header_head().unwrap();
 	let sync_head: chain::Tip = chain.get_sync_head().unwrap();
 
+	{
+		let mut status = sync_state.write().unwrap();
+		let highest_height = status.highest_height;
+		status.update(SyncState::BodySync {
+			current_height: body_head.height,
+			highest_height,
+		});
+	}
+
 	debug!(
 		LOGGER,
 		"body_sync: body_head - {}, {}, header_head - {}, {}, sync_head - {}, {}",
@@ -148,61 +384,376 @@ fn body_sync(peers: Arc<Peers>, chain: Arc<chain::Chain>) {
 				break;
 			}
 
-			hashes.push(header.hash());
+			hashes.push((header.height, header.hash()));
 			current = chain.get_block_header(&header.previous);
 		}
 	}
 	hashes.reverse();
 
-	// if we have 5 peers to sync from then ask for 50 blocks total (peer_count *
-	// 10) max will be 80 if all 8 peers are advertising more work
-	let peer_count = cmp::min(peers.more_work_peers().len(), 10);
-	let block_count = peer_count * 10;
+	// blocks we've already received or orphaned no longer need to be tracked,
+	// and anything that's been outstanding too long is assumed failed and
+	// freed up for another peer to try
+	let received = downloads.prune_received(&chain);
+	downloads.reap_timeouts();
+	if received > 0 {
+		sync_state.write().unwrap().blocks_received += received;
+	}
 
 	let hashes_to_get = hashes
-		.iter()
-		.filter(|x| {
-			// only ask for blocks that we have not yet processed
-			// either successfully stored or in our orphan list
-			!chain.get_block(x).is_ok() && !chain.is_orphan(x)
+		.into_iter()
+		.filter(|&(_, ref hash)| {
+			// only ask for blocks that we have not yet processed, stored
+			// in our orphan list, or already requested from a peer
+			!chain.get_block(hash).is_ok() && !chain.is_orphan(hash)
+				&& !downloads.is_in_transit(hash)
 		})
-		.take(block_count)
-		.cloned()
 		.collect::<Vec<_>>();
 
-	if hashes_to_get.len() > 0 {
-		debug!(
-			LOGGER,
-			"block_sync: {}/{} requesting blocks {:?} from {} peers",
-			body_head.height,
-			header_head.height,
-			hashes_to_get,
-			peer_count,
-		);
+	if hashes_to_get.is_empty() {
+		return;
+	}
 
-		for hash in hashes_to_get.clone() {
-			// TODO - Is there a threshold where we sync from most_work_peer (not
-			// more_work_peer)?
-			let peer = peers.more_work_peer();
-			if let Some(peer) = peer {
-				if let Ok(peer) = peer.try_read() {
-					if let Err(e) = peer.send_block_request(hash) {
-						debug!(LOGGER, "Skipped request to {}: {:?}", peer.info.addr, e);
-					}
+	let peers_list = peers.more_work_peers();
+	if peers_list.is_empty() {
+		return;
+	}
+
+	debug!(
+		LOGGER,
+		"block_sync: {}/{} requesting {} missing blocks from {} peers",
+		body_head.height,
+		header_head.height,
+		hashes_to_get.len(),
+		peers_list.len(),
+	);
+
+	// greedily walk the missing range and hand each hash to the next peer
+	// that's tall enough to have it and still has room under the per-peer
+	// in-transit cap, so many peers stay busy in parallel instead of
+	// serializing everything on a single `more_work_peer()`
+	let mut peer_idx = 0;
+	'hashes: for (height, hash) in hashes_to_get {
+		for _ in 0..peers_list.len() {
+			let peer = &peers_list[peer_idx % peers_list.len()];
+			peer_idx += 1;
+
+			if let Ok(peer) = peer.try_read() {
+				if peer.info.height < height {
+					continue;
+				}
+				if !downloads.is_reliable(&peer.info.addr) {
+					continue;
 				}
+				if downloads.in_transit_count(&peer.info.addr) >= MAX_BLOCKS_IN_TRANSIT_PER_PEER {
+					continue;
+				}
+				if let Err(e) = peer.send_block_request(hash) {
+					debug!(LOGGER, "Skipped request to {}: {:?}", peer.info.addr, e);
+					continue;
+				}
+				downloads.insert(hash, peer.info.addr);
+				continue 'hashes;
 			}
 		}
+		// no peer had the height or the spare capacity for this hash right
+		// now, leave it for the next tick
+		break;
 	}
 }
 
-fn header_sync(peers: Arc<Peers>, chain: Arc<chain::Chain>) {
+/// Minimum headers/sec we expect from whichever peer header_sync is
+/// currently pulling from.
+const HEADERS_PER_SECOND_MIN: f64 = 10.0;
+
+/// How long we watch a header peer before judging whether it's keeping up.
+const HEADER_RATE_INSPECTION_WINDOW: i64 = 30;
+
+/// How many consecutive slow inspection windows we tolerate from a peer
+/// before avoiding it in favor of alternatives. Keeps a brief hiccup from
+/// getting a peer punished for one bad sample.
+const MAX_SLOW_HEADER_SAMPLES: u32 = 2;
+
+/// Tracks how many headers our current header-sync peer delivers over a
+/// sliding inspection window, so a peer that's technically ahead of us but
+/// too slow to be useful gets rotated out in favor of the next-best one.
+struct HeaderSyncTracker {
+	inner: Mutex<HeaderSyncInner>,
+}
+
+struct HeaderSyncInner {
+	peer_addr: Option<SocketAddr>,
+	window_start: time::Tm,
+	window_start_height: u64,
+	slow_peers: HashMap<SocketAddr, u32>,
+}
+
+impl HeaderSyncTracker {
+	fn new() -> HeaderSyncTracker {
+		HeaderSyncTracker {
+			inner: Mutex::new(HeaderSyncInner {
+				peer_addr: None,
+				window_start: time::now_utc(),
+				window_start_height: 0,
+				slow_peers: HashMap::new(),
+			}),
+		}
+	}
+
+	/// Whether `addr` has been flagged slow often enough that we'd rather
+	/// try a different peer first.
+	fn is_slow(&self, addr: &SocketAddr) -> bool {
+		self.inner
+			.lock()
+			.unwrap()
+			.slow_peers
+			.get(addr)
+			.cloned()
+			.unwrap_or(0) >= MAX_SLOW_HEADER_SAMPLES
+	}
+
+	/// Called every header_sync tick with the peer we're about to use and
+	/// our current header height. Returns false once that peer has spent a
+	/// full inspection window delivering headers below the expected rate.
+	fn check(&self, addr: SocketAddr, height: u64) -> bool {
+		let mut inner = self.inner.lock().unwrap();
+
+		if inner.peer_addr != Some(addr) {
+			// new peer to watch, start a fresh window
+			inner.peer_addr = Some(addr);
+			inner.window_start = time::now_utc();
+			inner.window_start_height = height;
+			return true;
+		}
+
+		let elapsed = time::now_utc() - inner.window_start;
+		if elapsed < time::Duration::seconds(HEADER_RATE_INSPECTION_WINDOW) {
+			return true;
+		}
+
+		let headers = height.saturating_sub(inner.window_start_height);
+		let rate = headers as f64 / elapsed.num_seconds() as f64;
+
+		// reset the window regardless of the verdict so we don't keep
+		// judging against stale data
+		inner.window_start = time::now_utc();
+		inner.window_start_height = height;
+
+		if rate < HEADERS_PER_SECOND_MIN {
+			let count = inner.slow_peers.entry(addr).or_insert(0);
+			*count += 1;
+			warn!(
+				LOGGER,
+				"sync: {} delivered headers at {:.2}/s over {}s, below the expected {}/s",
+				addr,
+				rate,
+				HEADER_RATE_INSPECTION_WINDOW,
+				HEADERS_PER_SECOND_MIN,
+			);
+			false
+		} else {
+			inner.slow_peers.remove(&addr);
+			true
+		}
+	}
+}
+
+/// Hardcoded (height, hash) checkpoints we trust unconditionally, one list
+/// per chain type (ideally baked into `global` alongside the other
+/// chain-wide constants, once a `global` hook for this exists).
+///
+/// NOT YET POPULATED: no chain running against this codebase has enough
+/// confirmed, un-reorgable history yet to responsibly pin real heights and
+/// hashes here, and making some up would be worse than shipping nothing --
+/// a checkpoint that doesn't match the real chain just bans every honest
+/// peer. This is tracked plumbing for a follow-up (recording real
+/// checkpoints once a chain matures enough to have some), not a finished
+/// defense; `check_fork_checkpoints` stays a deliberate no-op until this is
+/// populated.
+fn fork_checkpoints() -> Vec<(u64, Hash)> {
+	vec![]
+}
+
+/// Tracks how far up the checkpoint-guarded header chain we've already
+/// verified, so `check_fork_checkpoints` only has to walk the headers
+/// received since the last check instead of re-walking all the way down
+/// to the lowest checkpoint on every `header_sync` tick.
+struct ForkCheckpointTracker {
+	last_checked_height: Mutex<u64>,
+}
+
+impl ForkCheckpointTracker {
+	fn new() -> ForkCheckpointTracker {
+		ForkCheckpointTracker {
+			last_checked_height: Mutex::new(0),
+		}
+	}
+}
+
+/// Returns the lowest checkpointed height, if any.
+fn lowest_checkpoint_height(checkpoints: &[(u64, Hash)]) -> Option<u64> {
+	checkpoints.iter().map(|&(height, _)| height).min()
+}
+
+/// Pure walk-back check: given a sequence of `(height, hash)` pairs for
+/// headers already walked from the tip downward, returns the first
+/// header whose hash fails to match the checkpoint at its height, as that
+/// header's own `(height, hash)`, if any. Kept
+/// free of `Chain`/`Peer` so the walk-back logic can be unit tested
+/// without a real chain or peer connection.
+fn find_checkpoint_mismatch(
+	headers: &[(u64, Hash)],
+	checkpoints: &[(u64, Hash)],
+) -> Option<(u64, Hash)> {
+	for &(height, hash) in headers {
+		if let Some(&(_, expected_hash)) =
+			checkpoints.iter().find(|&&(cp_height, _)| cp_height == height)
+		{
+			if hash != expected_hash {
+				return Some((height, hash));
+			}
+		}
+	}
+	None
+}
+
+/// Checks the header chain we're building from `peer` via `sync_head`
+/// against the hardcoded fork checkpoints, walking back from `sync_head`
+/// itself rather than the already-accepted `header_head`. This matters:
+/// by the time a header reaches `header_head` it's already been accepted,
+/// possibly from a different peer than the one we'd want to blame, so
+/// checking there can never actually stop a peer from steering us onto a
+/// bad fork. Checking `sync_head` catches it in the headers `peer` just
+/// sent us, before they're trusted.
+///
+/// The walk stops at whichever is higher: the lowest checkpoint, or the
+/// height we already verified on a previous tick, so the cost of this
+/// check stays bounded by how many headers arrived since then rather
+/// than growing with the chain.
+fn check_fork_checkpoints(
+	tracker: &ForkCheckpointTracker,
+	chain: &chain::Chain,
+	peer: &Peer,
+) -> bool {
+	let checkpoints = fork_checkpoints();
+	let lowest_checkpoint = match lowest_checkpoint_height(&checkpoints) {
+		Some(height) => height,
+		None => return true,
+	};
+
+	let sync_head = match chain.get_sync_head() {
+		Ok(tip) => tip,
+		Err(_) => return true,
+	};
+
+	let mut last_checked_height = tracker.last_checked_height.lock().unwrap();
+	let floor = lowest_checkpoint.max(*last_checked_height);
+	if sync_head.height <= floor {
+		return true;
+	}
+
+	let mut headers = Vec::new();
+	let mut current = chain.get_block_header(&sync_head.last_block_h);
+	while let Ok(header) = current {
+		headers.push((header.height, header.hash()));
+		if header.height <= floor {
+			break;
+		}
+		current = chain.get_block_header(&header.previous);
+	}
+
+	if let Some((height, hash)) = find_checkpoint_mismatch(&headers, &checkpoints) {
+		warn!(
+			LOGGER,
+			"sync: header {} at checkpoint height {} does not match the expected hash, {} is on a rejected fork",
+			hash,
+			height,
+			peer.info.addr,
+		);
+		peer.stop();
+		// discard the bad partial header chain so the next sync tick
+		// starts a fresh locator from our last trusted point instead of
+		// immediately re-flagging whichever peer we happen to talk to
+		// next
+		let _ = chain.reset_head();
+		*last_checked_height = lowest_checkpoint;
+		return false;
+	}
+
+	*last_checked_height = sync_head.height;
+	true
+}
+
+/// Picks the best peer to sync headers from: normally the most-work peer,
+/// but falls back to the next-best candidate that hasn't been flagged slow
+/// if the most-work peer has been underperforming.
+fn best_header_peer(
+	peers: &Arc<Peers>,
+	rate_tracker: &Arc<HeaderSyncTracker>,
+) -> Option<Arc<RwLock<Peer>>> {
+	let most_work = peers.most_work_peer();
+	let most_work_ok = most_work.as_ref().map_or(false, |peer| {
+		peer.try_read()
+			.map(|p| !rate_tracker.is_slow(&p.info.addr))
+			.unwrap_or(true)
+	});
+	if most_work_ok {
+		return most_work;
+	}
+
+	peers
+		.more_work_peers()
+		.into_iter()
+		.filter(|peer| {
+			peer.try_read()
+				.map(|p| !rate_tracker.is_slow(&p.info.addr))
+				.unwrap_or(false)
+		})
+		.max_by(|a, b| {
+			let da = a.try_read()
+				.map(|p| p.info.total_difficulty.clone())
+				.unwrap_or(Difficulty::zero());
+			let db = b.try_read()
+				.map(|p| p.info.total_difficulty.clone())
+				.unwrap_or(Difficulty::zero());
+			da.partial_cmp(&db).unwrap_or(CmpOrdering::Equal)
+		})
+		.or(most_work)
+}
+
+fn header_sync(
+	sync_state: &Arc<RwLock<SyncStatus>>,
+	rate_tracker: &Arc<HeaderSyncTracker>,
+	checkpoint_tracker: &Arc<ForkCheckpointTracker>,
+	peers: Arc<Peers>,
+	chain: Arc<chain::Chain>,
+) {
 	if let Ok(header_head) = chain.get_header_head() {
 		let difficulty = header_head.total_difficulty;
 
-		if let Some(peer) = peers.most_work_peer() {
+		if let Some(peer) = best_header_peer(&peers, rate_tracker) {
 			if let Ok(p) = peer.try_read() {
 				let peer_difficulty = p.info.total_difficulty.clone();
 				if peer_difficulty > difficulty {
+					if !check_fork_checkpoints(checkpoint_tracker, &chain, &p) {
+						return;
+					}
+
+					if !rate_tracker.check(p.info.addr, header_head.height) {
+						// this peer has spent a full inspection window below
+						// the expected rate; skip it this tick. Once it has been
+						// flagged slow MAX_SLOW_HEADER_SAMPLES windows in a row,
+						// best_header_peer passes it over for another candidate
+						return;
+					}
+
+					{
+						let mut status = sync_state.write().unwrap();
+						let highest_height = status.highest_height;
+						status.update(SyncState::HeaderSync {
+							current_height: header_head.height,
+							highest_height,
+						});
+					}
 					let _ = request_headers(peer.clone(), chain.clone());
 				}
 			}
@@ -255,13 +806,13 @@ fn request_headers(peer: Arc<RwLock<Peer>>, chain: Arc<chain::Chain>) -> Result<
 /// Whether we're currently syncing the chain or we're fully caught up and
 /// just receiving blocks through gossip.
 fn needs_syncing(
-	currently_syncing: &AtomicBool,
+	sync_state: &Arc<RwLock<SyncStatus>>,
 	peers: Arc<Peers>,
 	chain: Arc<chain::Chain>,
 ) -> (bool, u64) {
 	let local_diff = chain.total_difficulty();
 	let peer = peers.most_work_peer();
-	let is_syncing = currently_syncing.load(Ordering::Relaxed);
+	let is_syncing = sync_state.read().unwrap().state.is_syncing();
 	let mut most_work_height = 0;
 
 	// if we're already syncing, we're caught up if no peer has a higher
@@ -286,11 +837,13 @@ fn needs_syncing(
 					);
 
 					let _ = chain.reset_head();
+					sync_state.write().unwrap().update(SyncState::Synced);
 					return (false, 0);
 				}
 			}
 		} else {
 			warn!(LOGGER, "sync: no peers available, disabling sync");
+			sync_state.write().unwrap().update(SyncState::NoSync);
 			return (false, 0);
 		}
 	} else {
@@ -382,4 +935,185 @@ mod test {
 			]
 		);
 	}
+
+	#[test]
+	fn test_block_downloads_in_transit_tracking() {
+		let downloads = BlockDownloads::new();
+		let addr: SocketAddr = "127.0.0.1:3414".parse().unwrap();
+		let hash = Hash::from_vec(&[1u8; 32]);
+
+		assert!(!downloads.is_in_transit(&hash));
+		assert_eq!(downloads.in_transit_count(&addr), 0);
+
+		downloads.insert(hash, addr);
+
+		assert!(downloads.is_in_transit(&hash));
+		assert_eq!(downloads.in_transit_count(&addr), 1);
+	}
+
+	#[test]
+	fn test_block_downloads_is_reliable_threshold() {
+		let downloads = BlockDownloads::new();
+		let addr: SocketAddr = "127.0.0.1:3415".parse().unwrap();
+
+		assert!(downloads.is_reliable(&addr));
+
+		{
+			let mut timeouts = downloads.timeouts.lock().unwrap();
+			timeouts.insert(addr, (MAX_BLOCK_DOWNLOAD_TIMEOUTS - 1, time::now_utc()));
+		}
+		assert!(downloads.is_reliable(&addr));
+
+		{
+			let mut timeouts = downloads.timeouts.lock().unwrap();
+			timeouts.insert(addr, (MAX_BLOCK_DOWNLOAD_TIMEOUTS, time::now_utc()));
+		}
+		assert!(!downloads.is_reliable(&addr));
+	}
+
+	#[test]
+	fn test_block_downloads_timeout_strikes_decay() {
+		let downloads = BlockDownloads::new();
+		let addr: SocketAddr = "127.0.0.1:3416".parse().unwrap();
+
+		{
+			let mut timeouts = downloads.timeouts.lock().unwrap();
+			let stale = time::now_utc() - time::Duration::seconds(PEER_TIMEOUT_DECAY + 60);
+			timeouts.insert(addr, (MAX_BLOCK_DOWNLOAD_TIMEOUTS, stale));
+		}
+		assert!(!downloads.is_reliable(&addr));
+
+		// reap_timeouts also prunes strikes that are old enough to decay,
+		// independent of whether anything is currently in transit
+		downloads.reap_timeouts();
+
+		assert!(downloads.is_reliable(&addr));
+	}
+
+	#[test]
+	fn test_header_sync_tracker_new_peer_and_within_window() {
+		let tracker = HeaderSyncTracker::new();
+		let addr: SocketAddr = "127.0.0.1:3417".parse().unwrap();
+
+		// first call for a peer just starts the window, no verdict yet
+		assert!(tracker.check(addr, 100));
+		// the test runs in well under HEADER_RATE_INSPECTION_WINDOW, so this
+		// stays inside the window regardless of how many headers came in
+		assert!(tracker.check(addr, 105));
+		assert!(!tracker.is_slow(&addr));
+	}
+
+	#[test]
+	fn test_header_sync_tracker_starts_fresh_window_on_peer_change() {
+		let tracker = HeaderSyncTracker::new();
+		let addr_a: SocketAddr = "127.0.0.1:3418".parse().unwrap();
+		let addr_b: SocketAddr = "127.0.0.1:3419".parse().unwrap();
+
+		assert!(tracker.check(addr_a, 100));
+		// switching to a different peer resets the window rather than judging
+		// addr_b against addr_a's history
+		assert!(tracker.check(addr_b, 0));
+		assert_eq!(tracker.inner.lock().unwrap().peer_addr, Some(addr_b));
+	}
+
+	#[test]
+	fn test_header_sync_tracker_is_slow_threshold() {
+		let tracker = HeaderSyncTracker::new();
+		let addr: SocketAddr = "127.0.0.1:3420".parse().unwrap();
+
+		assert!(!tracker.is_slow(&addr));
+
+		{
+			let mut inner = tracker.inner.lock().unwrap();
+			inner.slow_peers.insert(addr, MAX_SLOW_HEADER_SAMPLES - 1);
+		}
+		assert!(!tracker.is_slow(&addr));
+
+		{
+			let mut inner = tracker.inner.lock().unwrap();
+			inner.slow_peers.insert(addr, MAX_SLOW_HEADER_SAMPLES);
+		}
+		assert!(tracker.is_slow(&addr));
+	}
+
+	#[test]
+	fn test_header_sync_tracker_judges_rate_once_window_elapses() {
+		let tracker = HeaderSyncTracker::new();
+		let addr: SocketAddr = "127.0.0.1:3421".parse().unwrap();
+
+		// force the window to already be in the past so the next check()
+		// judges the rate instead of waiting out the window
+		{
+			let mut inner = tracker.inner.lock().unwrap();
+			inner.peer_addr = Some(addr);
+			inner.window_start =
+				time::now_utc() - time::Duration::seconds(HEADER_RATE_INSPECTION_WINDOW + 1);
+			inner.window_start_height = 0;
+		}
+
+		// well below HEADERS_PER_SECOND_MIN over the window: flagged slow
+		assert!(!tracker.check(addr, 1));
+		assert_eq!(tracker.inner.lock().unwrap().slow_peers.get(&addr), Some(&1));
+
+		// a second consecutive slow window crosses MAX_SLOW_HEADER_SAMPLES
+		{
+			let mut inner = tracker.inner.lock().unwrap();
+			inner.window_start =
+				time::now_utc() - time::Duration::seconds(HEADER_RATE_INSPECTION_WINDOW + 1);
+			inner.window_start_height = 1;
+		}
+		assert!(!tracker.check(addr, 2));
+		assert!(tracker.is_slow(&addr));
+	}
+
+	#[test]
+	fn test_find_checkpoint_mismatch_matching_chain() {
+		let checkpoints = vec![(10, Hash::from_vec(&[1u8; 32])), (20, Hash::from_vec(&[2u8; 32]))];
+		// headers walked from tip downward; heights not in the checkpoint list
+		// are simply ignored
+		let headers = vec![
+			(25, Hash::from_vec(&[9u8; 32])),
+			(20, Hash::from_vec(&[2u8; 32])),
+			(15, Hash::from_vec(&[9u8; 32])),
+			(10, Hash::from_vec(&[1u8; 32])),
+		];
+		assert_eq!(find_checkpoint_mismatch(&headers, &checkpoints), None);
+	}
+
+	#[test]
+	fn test_find_checkpoint_mismatch_detects_mismatch() {
+		let checkpoints = vec![(10, Hash::from_vec(&[1u8; 32]))];
+		let headers = vec![
+			(15, Hash::from_vec(&[9u8; 32])),
+			// wrong hash at the checkpointed height -- this peer is on a
+			// different, rejected fork
+			(10, Hash::from_vec(&[0xffu8; 32])),
+		];
+		assert_eq!(
+			find_checkpoint_mismatch(&headers, &checkpoints),
+			Some((10, Hash::from_vec(&[0xffu8; 32])))
+		);
+	}
+
+	#[test]
+	fn test_find_checkpoint_mismatch_ignores_heights_outside_walk() {
+		// the walk stopped above the checkpoint height (e.g. bounded by a
+		// previously-checked floor), so the checkpoint never gets inspected
+		let checkpoints = vec![(10, Hash::from_vec(&[1u8; 32]))];
+		let headers = vec![(15, Hash::from_vec(&[9u8; 32])), (12, Hash::from_vec(&[9u8; 32]))];
+		assert_eq!(find_checkpoint_mismatch(&headers, &checkpoints), None);
+	}
+
+	#[test]
+	fn test_lowest_checkpoint_height() {
+		assert_eq!(lowest_checkpoint_height(&[]), None);
+		let checkpoints = vec![(20, Hash::from_vec(&[1u8; 32])), (10, Hash::from_vec(&[2u8; 32]))];
+		assert_eq!(lowest_checkpoint_height(&checkpoints), Some(10));
+	}
+
+	#[test]
+	fn test_fork_checkpoint_tracker_starts_at_zero() {
+		let tracker = ForkCheckpointTracker::new();
+		assert_eq!(*tracker.last_checked_height.lock().unwrap(), 0);
+	}
 }